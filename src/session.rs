@@ -1,15 +1,90 @@
 use crate::{AxumDatabasePool, AxumSessionData, AxumSessionID, AxumSessionStore, CookiesExt};
 use async_trait::async_trait;
 use axum_core::extract::{FromRequest, RequestParts};
-use cookie::CookieJar;
+use cookie::{Cookie, CookieJar};
 use http::{self, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
+use chrono::Utc;
+use dashmap::DashMap;
+use serde_json::Value;
 use std::{
+    collections::HashMap,
     fmt::Debug,
     marker::{Send, Sync},
+    sync::{Arc, RwLock},
+    time::Duration,
 };
 use uuid::Uuid;
 
+/// Removes `key` from `data`, reporting whether it was present and
+/// deserializing the removed value if so.
+fn take_value<T: DeserializeOwned>(data: &mut HashMap<String, Value>, key: &str) -> (Option<T>, bool) {
+    match data.remove(key) {
+        Some(value) => (serde_json::from_value(value).ok(), true),
+        None => (None, false),
+    }
+}
+
+/// Controls when a Session is written back to the backing store.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PersistencePolicy {
+    /// Always persist the session.
+    Always,
+    /// Only persist a session that already existed when it was loaded.
+    ExistingOnly,
+    /// Only persist a session that was modified and is not empty.
+    ChangedOnly,
+}
+
+impl Default for PersistencePolicy {
+    fn default() -> Self {
+        PersistencePolicy::ChangedOnly
+    }
+}
+
+/// Returns whether a session expiring at `expires` is still live at `now`.
+fn not_expired(expires: chrono::DateTime<Utc>, now: chrono::DateTime<Utc>) -> bool {
+    expires > now
+}
+
+/// Moves `old_key`'s entry to `new_key`, inserting a fresh default entry if
+/// `old_key` isn't present rather than leaving `new_key` unpopulated. Marks
+/// the moved entry `dirty` since the id rotation itself must reach the
+/// client regardless of what the data looked like under the old key.
+fn remap_session_id(inner: &DashMap<String, AxumSessionData>, old_key: &str, new_key: &str) {
+    let mut data = inner
+        .remove(old_key)
+        .map(|(_, data)| data)
+        .unwrap_or_default();
+
+    data.dirty = true;
+    inner.insert(new_key.to_string(), data);
+}
+
+/// Decides whether a session should be persisted given its policy and state.
+///
+/// `id_changed` forces persistence regardless of policy: once a session's id
+/// has rotated, the new cookie must reach the client even if the data itself
+/// is unchanged or empty, or the session becomes unreachable under its old
+/// cookie while its data sits orphaned under the new one.
+fn persistence_decision(
+    policy: PersistencePolicy,
+    existed_on_load: bool,
+    dirty: bool,
+    empty: bool,
+    id_changed: bool,
+) -> bool {
+    if id_changed {
+        return true;
+    }
+
+    match policy {
+        PersistencePolicy::Always => true,
+        PersistencePolicy::ExistingOnly => existed_on_load,
+        PersistencePolicy::ChangedOnly => dirty && !empty,
+    }
+}
+
 /// A Session Store.
 ///
 /// Provides a Storage Handler to AxumSessionStore and contains the AxumSessionID(UUID) of the current session.
@@ -21,7 +96,9 @@ where
     T: AxumDatabasePool + Clone + Debug + Sync + Send + 'static,
 {
     pub(crate) store: AxumSessionStore<T>,
-    pub(crate) id: AxumSessionID,
+    pub(crate) id: Arc<RwLock<AxumSessionID>>,
+    pub(crate) existed_on_load: bool,
+    pub(crate) regenerated: Arc<RwLock<bool>>,
 }
 
 /// Adds FromRequest<B> for AxumSession
@@ -52,20 +129,29 @@ where
             .get_cookie(&store.config.cookie_name, &store.config.key)
             .and_then(|c| Uuid::parse_str(c.value()).ok());
 
-        let uuid = match value {
-            Some(v) => v,
-            None => loop {
-                let token = Uuid::new_v4();
+        let (uuid, existed_on_load) = match value {
+            Some(v) => {
+                let existed = store.inner.contains_key(&v.to_string());
+                (v, existed)
+            }
+            None => {
+                let token = loop {
+                    let token = Uuid::new_v4();
 
-                if !store.inner.contains_key(&token.to_string()) {
-                    break token;
-                }
-            },
+                    if !store.inner.contains_key(&token.to_string()) {
+                        break token;
+                    }
+                };
+
+                (token, false)
+            }
         };
 
         AxumSession {
-            id: AxumSessionID(uuid),
+            id: Arc::new(RwLock::new(AxumSessionID(uuid))),
             store: store.clone(),
+            existed_on_load,
+            regenerated: Arc::new(RwLock::new(false)),
         }
     }
     /// Runs a Closure upon the Current Sessions stored data to get or set session data.
@@ -75,8 +161,8 @@ where
     /// # Examples
     /// ```rust no_run
     /// session.tap(|sess| {
-    ///   let string = sess.data.get(key)?;
-    ///   serde_json::from_str(string).ok()
+    ///   let value = sess.data.get(key)?;
+    ///   serde_json::from_value(value.clone()).ok()
     /// }).await;
     /// ```
     ///
@@ -84,7 +170,9 @@ where
         &self,
         func: impl FnOnce(&mut AxumSessionData) -> Option<T>,
     ) -> Option<T> {
-        if let Some(mut instance) = self.store.inner.get_mut(&self.id.0.to_string()) {
+        let key = self.id.read().unwrap().0.to_string();
+
+        if let Some(mut instance) = self.store.inner.get_mut(&key) {
             func(&mut instance)
         } else {
             tracing::warn!("Session data unexpectedly missing");
@@ -92,6 +180,47 @@ where
         }
     }
 
+    /// Regenerates the Session's ID, moving its data under a newly allocated
+    /// `Uuid` so the Session Layer re-issues the cookie with the new id.
+    ///
+    /// Call this on privilege changes such as login or logout to prevent
+    /// session fixation.
+    ///
+    /// # Examples
+    /// ```rust no_run
+    /// session.regenerate().await;
+    /// ```
+    ///
+    pub async fn regenerate(&self) {
+        let new_id = loop {
+            let token = Uuid::new_v4();
+
+            if !self.store.inner.contains_key(&token.to_string()) {
+                break token;
+            }
+        };
+
+        let old_key = self.id.read().unwrap().0.to_string();
+        remap_session_id(&self.store.inner, &old_key, &new_id.to_string());
+        *self.id.write().unwrap() = AxumSessionID(new_id);
+        *self.regenerated.write().unwrap() = true;
+    }
+
+    /// Clears all of the Session's data and regenerates its ID in one step.
+    ///
+    /// Intended for logout flows where both the data and the identifier
+    /// should be reset.
+    ///
+    /// # Examples
+    /// ```rust no_run
+    /// session.clear_and_regenerate().await;
+    /// ```
+    ///
+    pub async fn clear_and_regenerate(&self) {
+        self.clear_all().await;
+        self.regenerate().await;
+    }
+
     /// Sets the Current Session to be Destroyed on the next run.
     ///
     /// # Examples
@@ -115,7 +244,10 @@ where
     ///
     pub async fn set_longterm(&self, longterm: bool) {
         self.tap(|sess| {
-            sess.longterm = longterm;
+            if sess.longterm != longterm {
+                sess.longterm = longterm;
+                sess.dirty = true;
+            }
             Some(1)
         });
     }
@@ -132,7 +264,10 @@ where
     ///
     pub async fn set_store(&self, storable: bool) {
         self.tap(|sess| {
-            sess.storable = storable;
+            if sess.storable != storable {
+                sess.storable = storable;
+                sess.dirty = true;
+            }
             Some(1)
         });
     }
@@ -150,8 +285,38 @@ where
     ///Used to get data stored within SessionDatas hashmap from a key value.
     pub async fn get<T: serde::de::DeserializeOwned>(&self, key: &str) -> Option<T> {
         self.tap(|sess| {
-            let string = sess.data.get(key)?;
-            serde_json::from_str(string).ok()
+            let value = sess.data.get(key)?;
+            serde_json::from_value(value.clone()).ok()
+        })
+    }
+
+    /// Gets the raw `serde_json::Value` stored within the Session's HashMap.
+    ///
+    /// Returns None if the Key does not exist.
+    ///
+    /// # Examples
+    /// ```rust no_run
+    /// let value = session.get_raw("user-id").await.unwrap();
+    /// ```
+    ///
+    pub async fn get_raw(&self, key: &str) -> Option<Value> {
+        self.tap(|sess| sess.data.get(key).cloned())
+    }
+
+    /// Removes a Key from the Session's HashMap and deserializes it in one step.
+    ///
+    /// # Examples
+    /// ```rust no_run
+    /// let flash: Option<String> = session.take("flash").await;
+    /// ```
+    ///
+    pub async fn take<T: serde::de::DeserializeOwned>(&self, key: &str) -> Option<T> {
+        self.tap(|sess| {
+            let (value, removed) = take_value(&mut sess.data, key);
+            if removed {
+                sess.dirty = true;
+            }
+            value
         })
     }
 
@@ -163,11 +328,12 @@ where
     /// ```
     ///
     pub async fn set(&self, key: &str, value: impl Serialize) {
-        let value = serde_json::to_string(&value).unwrap_or_else(|_| "".to_string());
+        let value = serde_json::to_value(&value).unwrap_or(Value::Null);
 
         self.tap(|sess| {
             if sess.data.get(key) != Some(&value) {
                 sess.data.insert(key.to_string(), value);
+                sess.dirty = true;
             }
             Some(1)
         });
@@ -181,7 +347,13 @@ where
     /// ```
     ///
     pub async fn remove(&self, key: &str) {
-        self.tap(|sess| sess.data.remove(key));
+        self.tap(|sess| {
+            let removed = sess.data.remove(key);
+            if removed.is_some() {
+                sess.dirty = true;
+            }
+            removed
+        });
     }
 
     /// Clears all data from the Current Session's HashMap.
@@ -192,7 +364,12 @@ where
     /// ```
     ///
     pub async fn clear_all(&self) {
-        if let Some(mut instance) = self.store.inner.get_mut(&self.id.0.to_string()) {
+        let key = self.id.read().unwrap().0.to_string();
+
+        if let Some(mut instance) = self.store.inner.get_mut(&key) {
+            if !instance.data.is_empty() {
+                instance.dirty = true;
+            }
             instance.data.clear();
         }
 
@@ -201,6 +378,43 @@ where
         }
     }
 
+    /// Returns whether the Current Session should be written back to the
+    /// backing store, per the `AxumSessionStore`'s configured
+    /// `PersistencePolicy`. The Session Layer's save path calls this to
+    /// decide whether to issue a `Set-Cookie` header and perform the write.
+    pub fn should_persist(&self) -> bool {
+        let key = self.id.read().unwrap().0.to_string();
+        let (dirty, empty) = self
+            .store
+            .inner
+            .get(&key)
+            .map(|sess| (sess.dirty, sess.data.is_empty()))
+            .unwrap_or((false, true));
+
+        persistence_decision(
+            self.store.config.persistence_policy,
+            self.existed_on_load,
+            dirty,
+            empty,
+            *self.regenerated.read().unwrap(),
+        )
+    }
+
+    /// Builds the `Set-Cookie` for this response, or returns `None` when
+    /// `should_persist` says this session isn't worth a round trip.
+    ///
+    /// This is the save path's entry point: the Session Layer calls it once
+    /// per response and, on `None`, skips both issuing the cookie and
+    /// writing to the backing database.
+    pub fn finalize_cookie(&self) -> Option<Cookie<'static>> {
+        if !self.should_persist() {
+            return None;
+        }
+
+        let id = self.id.read().unwrap().0.to_string();
+        Some(Cookie::new(self.store.config.cookie_name.clone(), id))
+    }
+
     /// Returns a i64 count of how many Sessions exist.
     ///
     /// If the Session is persistant it will return all sessions within the database.
@@ -219,3 +433,249 @@ where
         }
     }
 }
+
+/// The key `AxumTypedSession` stores its generic payload under within the
+/// underlying `AxumSession`'s data map.
+const TYPED_DATA_KEY: &str = "__axum_session_typed_data__";
+
+/// A Session Store parameterized over an application-chosen data type `D`.
+///
+/// Lets the application work with one strongly-typed blob through
+/// `tap`/`tap_mut` instead of juggling string keys.
+#[derive(Debug, Clone)]
+pub struct AxumTypedSession<S, D>
+where
+    S: AxumDatabasePool + Clone + Debug + Sync + Send + 'static,
+    D: Serialize + DeserializeOwned + Default + Clone + Debug + Sync + Send + 'static,
+{
+    pub(crate) inner: AxumSession<S>,
+    _marker: std::marker::PhantomData<D>,
+}
+
+/// Adds FromRequest<B> for AxumTypedSession
+///
+/// Returns the AxumTypedSession wrapping the AxumSession from Axums request
+/// extensions.
+#[async_trait]
+impl<B, S, D> FromRequest<B> for AxumTypedSession<S, D>
+where
+    B: Send,
+    S: AxumDatabasePool + Clone + Debug + Sync + Send + 'static,
+    D: Serialize + DeserializeOwned + Default + Clone + Debug + Sync + Send + 'static,
+{
+    type Rejection = (http::StatusCode, &'static str);
+
+    async fn from_request(req: &mut RequestParts<B>) -> Result<Self, Self::Rejection> {
+        let inner = AxumSession::<S>::from_request(req).await?;
+
+        Ok(AxumTypedSession {
+            inner,
+            _marker: std::marker::PhantomData,
+        })
+    }
+}
+
+impl<S, D> AxumTypedSession<S, D>
+where
+    S: AxumDatabasePool + Clone + Debug + Sync + Send + 'static,
+    D: Serialize + DeserializeOwned + Default + Clone + Debug + Sync + Send + 'static,
+{
+    /// Runs a closure against a read-only reference to the typed session data.
+    ///
+    /// # Examples
+    /// ```rust no_run
+    /// let count = session.tap(|sess: &MyData| sess.count).await;
+    /// ```
+    ///
+    pub async fn tap<R>(&self, func: impl FnOnce(&D) -> R) -> R {
+        let data = self
+            .inner
+            .get_raw(TYPED_DATA_KEY)
+            .await
+            .and_then(|value| serde_json::from_value(value).ok())
+            .unwrap_or_default();
+
+        func(&data)
+    }
+
+    /// Runs a closure against a mutable reference to the typed session data
+    /// and persists any changes it makes back into the Session.
+    ///
+    /// Reads and writes happen under a single lock on the underlying
+    /// session entry, so concurrent `tap_mut` calls can't race and silently
+    /// drop one another's update.
+    ///
+    /// # Examples
+    /// ```rust no_run
+    /// session.tap_mut(|sess: &mut MyData| sess.count += 1).await;
+    /// ```
+    ///
+    pub async fn tap_mut<R>(&self, func: impl FnOnce(&mut D) -> R) -> R {
+        let key = self.inner.id.read().unwrap().0.to_string();
+
+        if let Some(mut instance) = self.inner.store.inner.get_mut(&key) {
+            let mut data: D = instance
+                .data
+                .get(TYPED_DATA_KEY)
+                .and_then(|value| serde_json::from_value(value.clone()).ok())
+                .unwrap_or_default();
+
+            let result = func(&mut data);
+
+            if let Ok(value) = serde_json::to_value(&data) {
+                if instance.data.get(TYPED_DATA_KEY) != Some(&value) {
+                    instance.data.insert(TYPED_DATA_KEY.to_string(), value);
+                    instance.dirty = true;
+                }
+            }
+
+            result
+        } else {
+            tracing::warn!("Session data unexpectedly missing");
+            func(&mut D::default())
+        }
+    }
+}
+
+impl<T> AxumSessionStore<T>
+where
+    T: AxumDatabasePool + Clone + Debug + Sync + Send + 'static,
+{
+    /// Spawns a background task that calls `remove_expired` on `interval`.
+    ///
+    /// # Examples
+    /// ```rust no_run
+    /// store.spawn_cleanup(std::time::Duration::from_secs(60));
+    /// ```
+    ///
+    pub fn spawn_cleanup(&self, interval: Duration) {
+        let store = self.clone();
+
+        tokio::spawn(async move {
+            let mut ticker = tokio::time::interval(interval);
+
+            loop {
+                ticker.tick().await;
+                store.remove_expired().await;
+            }
+        });
+    }
+
+    /// Removes all expired sessions from memory, and from the backing
+    /// database when the store is persistent.
+    ///
+    /// # Examples
+    /// ```rust no_run
+    /// store.remove_expired().await;
+    /// ```
+    ///
+    pub async fn remove_expired(&self) {
+        let now = Utc::now();
+        self.inner.retain(|_, sess| not_expired(sess.expires, now));
+
+        if let Some(client) = &self.client {
+            if let Err(err) = client.delete_expired().await {
+                tracing::error!("Failed to delete expired sessions from the database: {}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_value_removes_and_deserializes_present_key() {
+        let mut data = HashMap::new();
+        data.insert("id".to_string(), Value::from(42));
+
+        let (value, removed): (Option<i32>, bool) = take_value(&mut data, "id");
+
+        assert_eq!(value, Some(42));
+        assert!(removed);
+        assert!(!data.contains_key("id"));
+    }
+
+    #[test]
+    fn take_value_reports_missing_key() {
+        let mut data: HashMap<String, Value> = HashMap::new();
+
+        let (value, removed): (Option<i32>, bool) = take_value(&mut data, "id");
+
+        assert_eq!(value, None);
+        assert!(!removed);
+    }
+
+    #[test]
+    fn take_value_still_removes_on_deserialize_mismatch() {
+        let mut data = HashMap::new();
+        data.insert("id".to_string(), Value::from("not-a-number"));
+
+        let (value, removed): (Option<i32>, bool) = take_value(&mut data, "id");
+
+        assert_eq!(value, None);
+        assert!(removed);
+        assert!(!data.contains_key("id"));
+    }
+
+    #[test]
+    fn not_expired_keeps_future_expiry_and_drops_past() {
+        let now = Utc::now();
+
+        assert!(not_expired(now + chrono::Duration::seconds(1), now));
+        assert!(!not_expired(now - chrono::Duration::seconds(1), now));
+        assert!(!not_expired(now, now));
+    }
+
+    #[test]
+    fn remap_session_id_backfills_missing_old_key() {
+        let inner: DashMap<String, AxumSessionData> = DashMap::new();
+
+        remap_session_id(&inner, "old", "new");
+
+        assert!(inner.contains_key("new"));
+        assert!(!inner.contains_key("old"));
+        assert!(inner.get("new").unwrap().dirty);
+    }
+
+    #[test]
+    fn remap_session_id_moves_existing_data_and_marks_it_dirty() {
+        let inner: DashMap<String, AxumSessionData> = DashMap::new();
+        inner.insert("old".to_string(), AxumSessionData::default());
+
+        remap_session_id(&inner, "old", "new");
+
+        assert!(!inner.contains_key("old"));
+        assert!(inner.get("new").unwrap().dirty);
+    }
+
+    #[test]
+    fn persistence_policy_defaults_to_changed_only() {
+        assert_eq!(PersistencePolicy::default(), PersistencePolicy::ChangedOnly);
+    }
+
+    #[test]
+    fn persistence_decision_always_persists() {
+        assert!(persistence_decision(PersistencePolicy::Always, false, false, true, false));
+    }
+
+    #[test]
+    fn persistence_decision_existing_only_ignores_dirty_state() {
+        assert!(persistence_decision(PersistencePolicy::ExistingOnly, true, false, true, false));
+        assert!(!persistence_decision(PersistencePolicy::ExistingOnly, false, true, false, false));
+    }
+
+    #[test]
+    fn persistence_decision_changed_only_requires_dirty_and_non_empty() {
+        assert!(persistence_decision(PersistencePolicy::ChangedOnly, false, true, false, false));
+        assert!(!persistence_decision(PersistencePolicy::ChangedOnly, false, true, true, false));
+        assert!(!persistence_decision(PersistencePolicy::ChangedOnly, false, false, false, false));
+    }
+
+    #[test]
+    fn persistence_decision_id_change_forces_persist_even_if_empty_and_unchanged() {
+        assert!(persistence_decision(PersistencePolicy::ChangedOnly, false, false, true, true));
+        assert!(persistence_decision(PersistencePolicy::ExistingOnly, false, false, true, true));
+    }
+}